@@ -0,0 +1,479 @@
+//! Support for running a benchmark target on a remote host.
+//!
+//! A remote host runs this module's `serve` function as a small daemon. The
+//! local `cargo-criterion` process connects to the daemon's control port,
+//! streams over the compiled executable and the `CRITERION_HOME` files it
+//! needs, and the daemon spawns the benchmark locally and forwards the port
+//! it listens on back to us so the existing `Connection` handshake completes
+//! as if the target were running on `localhost`.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Errors that can occur while shipping a target to, or launching it on, a
+/// remote agent.
+#[derive(Debug)]
+pub enum AgentError {
+    IoError(std::io::Error),
+    TargetTripleMismatch { expected: String, actual: String },
+    SpawnFailed(String),
+}
+impl std::fmt::Display for AgentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AgentError::IoError(err) => write!(f, "IO error talking to remote agent: {}", err),
+            AgentError::TargetTripleMismatch { expected, actual } => write!(
+                f,
+                "Remote agent target triple '{}' does not match uploaded binary's '{}'",
+                actual, expected
+            ),
+            AgentError::SpawnFailed(message) => {
+                write!(f, "Remote agent failed to spawn benchmark: {}", message)
+            }
+        }
+    }
+}
+impl std::error::Error for AgentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AgentError::IoError(err) => Some(err),
+            AgentError::TargetTripleMismatch { .. } => None,
+            AgentError::SpawnFailed(_) => None,
+        }
+    }
+}
+impl From<std::io::Error> for AgentError {
+    fn from(err: std::io::Error) -> Self {
+        AgentError::IoError(err)
+    }
+}
+
+/// A request sent over the control channel to launch a benchmark on a
+/// remote agent.
+struct LaunchRequest<'a> {
+    target_triple: &'a str,
+    executable: &'a [u8],
+    criterion_home_files: &'a [(PathBuf, Vec<u8>)],
+    args: &'a [std::ffi::OsString],
+    /// The fingerprint of the TLS certificate `cargo-criterion` will use
+    /// to secure the forwarded connection, if any. Passed on to the child
+    /// via `CARGO_CRITERION_TLS_FINGERPRINT` so it can pin it.
+    tls_fingerprint: Option<&'a str>,
+}
+
+/// The handle a client holds on a benchmark that was launched remotely.
+pub struct RemoteHandle {
+    control: TcpStream,
+    /// The port the agent forwards traffic to us on; for a remote target we
+    /// connect to this port on the agent's host and run the `Connection`
+    /// handshake over that socket, instead of accepting on a local
+    /// `TcpListener`.
+    pub forwarded_port: u16,
+}
+impl RemoteHandle {
+    /// Tell the agent to terminate the child process and clean up its temp
+    /// file. Called when the connection drops or the run is cancelled.
+    pub fn kill_and_cleanup(mut self) -> Result<(), AgentError> {
+        write_frame(&mut self.control, b"KILL")?;
+        Ok(())
+    }
+}
+
+/// Connect to the agent daemon at `addr`, upload the executable and
+/// `CRITERION_HOME` contents, and ask it to launch the benchmark.
+pub fn launch_remote(
+    addr: SocketAddr,
+    target_triple: &str,
+    executable_path: &Path,
+    criterion_home: &Path,
+    args: &[std::ffi::OsString],
+    tls_fingerprint: Option<&str>,
+) -> Result<RemoteHandle, AgentError> {
+    let executable = fs::read(executable_path)?;
+    let criterion_home_files = read_dir_recursive(criterion_home)?;
+
+    let mut control = TcpStream::connect(addr)?;
+    let request = LaunchRequest {
+        target_triple,
+        executable: &executable,
+        criterion_home_files: &criterion_home_files,
+        args,
+        tls_fingerprint,
+    };
+    send_launch_request(&mut control, &request)?;
+
+    let response = read_frame(&mut control)?;
+    if let Some(rest) = response.strip_prefix(b"ERR ") {
+        return Err(AgentError::SpawnFailed(
+            String::from_utf8_lossy(rest).into_owned(),
+        ));
+    }
+    if let Some(rest) = response.strip_prefix(b"MISMATCH ") {
+        return Err(AgentError::TargetTripleMismatch {
+            expected: target_triple.to_string(),
+            actual: String::from_utf8_lossy(rest).into_owned(),
+        });
+    }
+    let port_str = response
+        .strip_prefix(b"OK ")
+        .ok_or_else(|| AgentError::SpawnFailed("malformed agent response".to_string()))?;
+    let port: u16 = String::from_utf8_lossy(port_str)
+        .trim()
+        .parse()
+        .map_err(|_| AgentError::SpawnFailed("malformed port in agent response".to_string()))?;
+
+    Ok(RemoteHandle {
+        control,
+        forwarded_port: port,
+    })
+}
+
+/// Run the agent daemon, accepting launch requests on `listener`. Each
+/// connection is handled on its own thread so several benchmarks can be
+/// launched and monitored concurrently, which is exactly what happens when
+/// `crate::scheduler` runs more than one `Remote` target against the same
+/// agent at once.
+pub fn serve(listener: TcpListener, local_target_triple: &str) -> Result<(), AgentError> {
+    loop {
+        let (control, _) = listener.accept()?;
+        let local_target_triple = local_target_triple.to_string();
+        std::thread::spawn(move || handle_one_launch(control, &local_target_triple));
+    }
+}
+
+/// A unique id for each launch handled by this daemon, so two concurrent
+/// launches never share a temp path even though the daemon itself is
+/// long-lived and keeps a single pid for its whole lifetime.
+static NEXT_LAUNCH_ID: AtomicU64 = AtomicU64::new(0);
+
+fn next_launch_id() -> u64 {
+    NEXT_LAUNCH_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Handle a single launch request end-to-end: spawn the benchmark, report
+/// back on `control`, then keep `control` open for the lifetime of the
+/// child so a `KILL` command or the client disconnecting is noticed and
+/// cleans things up, rather than relying solely on the child exiting on its
+/// own.
+fn handle_one_launch(mut control: TcpStream, local_target_triple: &str) {
+    match try_handle_one_launch(&mut control, local_target_triple) {
+        Ok(Some((child, temp_path, criterion_home))) => {
+            spawn_cleanup_watcher(child, control, temp_path, criterion_home);
+        }
+        Ok(None) => {
+            // Target triple mismatch; the response has already been
+            // written and there is nothing running to clean up.
+        }
+        Err(err) => {
+            let message = format!("ERR {}\n", err);
+            let _ = write_frame(&mut control, message.as_bytes());
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn try_handle_one_launch(
+    control: &mut TcpStream,
+    local_target_triple: &str,
+) -> Result<Option<(Child, PathBuf, PathBuf)>, AgentError> {
+    let (target_triple, executable, criterion_home_files, args, tls_fingerprint) =
+        recv_launch_request(control)?;
+
+    if target_triple != local_target_triple {
+        let message = format!("MISMATCH {}\n", local_target_triple);
+        write_frame(control, message.as_bytes())?;
+        return Ok(None);
+    }
+
+    let launch_id = next_launch_id();
+    let temp_path = std::env::temp_dir().join(format!(
+        "cargo-criterion-agent-{}-{}",
+        std::process::id(),
+        launch_id
+    ));
+    fs::write(&temp_path, &executable)?;
+    set_executable(&temp_path)?;
+
+    let criterion_home = std::env::temp_dir().join(format!(
+        "cargo-criterion-home-{}-{}",
+        std::process::id(),
+        launch_id
+    ));
+    for (relative_path, contents) in criterion_home_files {
+        let full_path = criterion_home.join(relative_path);
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(full_path, contents)?;
+    }
+
+    // Two separate listeners, not one: the child connects to `child_listener`
+    // on loopback, and the caller connects to `public_listener` over the
+    // network once we report `forwarded_port` back. Keeping them distinct
+    // means we always know which accepted socket is which side of the
+    // tunnel, rather than guessing from accept order.
+    let child_listener = TcpListener::bind("127.0.0.1:0")?;
+    let child_port = child_listener.local_addr()?.port();
+    let public_listener = TcpListener::bind("0.0.0.0:0")?;
+    let forwarded_port = public_listener.local_addr()?.port();
+
+    let mut command = Command::new(&temp_path);
+    command
+        .arg("--bench")
+        .args(&args)
+        .env("CRITERION_HOME", &criterion_home)
+        .env("CARGO_CRITERION_PORT", child_port.to_string())
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::inherit());
+
+    if let Some(fingerprint) = &tls_fingerprint {
+        command.env("CARGO_CRITERION_TLS_FINGERPRINT", fingerprint);
+    }
+
+    let child = command
+        .spawn()
+        .map_err(|err| AgentError::SpawnFailed(err.to_string()))?;
+
+    spawn_port_relay(child_listener, public_listener);
+
+    let message = format!("OK {}\n", forwarded_port);
+    write_frame(control, message.as_bytes())?;
+
+    Ok(Some((child, temp_path, criterion_home)))
+}
+
+/// Splice the benchmark's local connection through to the network
+/// connection the remote caller makes on `forwarded_port`, so the existing
+/// `Connection` handshake works unmodified no matter which side it's really
+/// running on.
+///
+/// Accepts exactly one connection on each listener (a launch only ever
+/// involves one benchmark), then copies bytes between them in both
+/// directions until either side closes.
+fn spawn_port_relay(child_listener: TcpListener, public_listener: TcpListener) {
+    std::thread::spawn(move || {
+        let child_socket = match child_listener.accept() {
+            Ok((socket, _)) => socket,
+            Err(_) => return,
+        };
+        let public_socket = match public_listener.accept() {
+            Ok((socket, _)) => socket,
+            Err(_) => return,
+        };
+        relay_bidirectional(child_socket, public_socket);
+    });
+}
+
+/// Copy bytes in both directions between `a` and `b` until one side hits
+/// EOF or an error, then shut the other side's write half down so its peer
+/// also sees EOF instead of hanging.
+fn relay_bidirectional(a: TcpStream, b: TcpStream) {
+    let (a_read, b_write) = match (a.try_clone(), b.try_clone()) {
+        (Ok(a_read), Ok(b_write)) => (a_read, b_write),
+        _ => return,
+    };
+    let forward = std::thread::spawn(move || {
+        let mut a_read = a_read;
+        let mut b_write = b_write;
+        let _ = io::copy(&mut a_read, &mut b_write);
+        let _ = b_write.shutdown(std::net::Shutdown::Write);
+    });
+
+    let mut b_read = b;
+    let mut a_write = a;
+    let _ = io::copy(&mut b_read, &mut a_write);
+    let _ = a_write.shutdown(std::net::Shutdown::Write);
+
+    let _ = forward.join();
+}
+
+/// Watch both the child and the control connection: whichever finishes
+/// first (the benchmark exiting on its own, a `KILL` command, or the
+/// client simply disconnecting) triggers killing the child and removing
+/// its temp files exactly once.
+fn spawn_cleanup_watcher(
+    child: Child,
+    mut control: TcpStream,
+    temp_path: PathBuf,
+    criterion_home: PathBuf,
+) {
+    let child = Arc::new(Mutex::new(child));
+    let cleaned_up = Arc::new(AtomicBool::new(false));
+
+    let control_child = Arc::clone(&child);
+    let control_cleaned_up = Arc::clone(&cleaned_up);
+    let control_temp_path = temp_path.clone();
+    let control_criterion_home = criterion_home.clone();
+    std::thread::spawn(move || {
+        loop {
+            match read_frame(&mut control) {
+                Ok(frame) if frame == b"KILL" => break,
+                Ok(_) => continue,
+                Err(_) => break, // EOF or the connection dropped.
+            }
+        }
+        cleanup_once(
+            &control_child,
+            &control_cleaned_up,
+            &control_temp_path,
+            &control_criterion_home,
+        );
+    });
+
+    std::thread::spawn(move || {
+        // `try_wait` rather than a blocking `wait`: the latter would hold
+        // the `MutexGuard` for as long as the child runs, and the
+        // control-watcher thread above would never get the lock to call
+        // `kill()` on a `KILL` command or disconnect until the child was
+        // about to exit on its own anyway.
+        loop {
+            match child.lock().unwrap().try_wait() {
+                Ok(Some(_)) | Err(_) => break,
+                Ok(None) => {}
+            }
+            std::thread::sleep(REAP_POLL_INTERVAL);
+        }
+        cleanup_once(&child, &cleaned_up, &temp_path, &criterion_home);
+    });
+}
+
+/// How often the reaper thread in `spawn_cleanup_watcher` polls the child
+/// with a non-blocking `try_wait`.
+const REAP_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Kill the child and remove its temp files, but only once, however many
+/// of the watcher threads race to call this.
+fn cleanup_once(child: &Mutex<Child>, cleaned_up: &AtomicBool, temp_path: &Path, criterion_home: &Path) {
+    if !cleaned_up.swap(true, Ordering::SeqCst) {
+        let _ = child.lock().unwrap().kill();
+        let _ = fs::remove_file(temp_path);
+        let _ = fs::remove_dir_all(criterion_home);
+    }
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_mode(0o755);
+    fs::set_permissions(path, perms)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn read_dir_recursive(dir: &Path) -> io::Result<Vec<(PathBuf, Vec<u8>)>> {
+    let mut files = Vec::new();
+    if !dir.exists() {
+        return Ok(files);
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_dir() {
+            for (relative, contents) in read_dir_recursive(&path)? {
+                files.push((entry.file_name().into(), contents).with_relative(relative));
+            }
+        } else {
+            files.push((PathBuf::from(entry.file_name()), fs::read(&path)?));
+        }
+    }
+    Ok(files)
+}
+
+trait WithRelative {
+    fn with_relative(self, relative: PathBuf) -> (PathBuf, Vec<u8>);
+}
+impl WithRelative for (PathBuf, Vec<u8>) {
+    fn with_relative(self, relative: PathBuf) -> (PathBuf, Vec<u8>) {
+        (self.0.join(relative), self.1)
+    }
+}
+
+fn write_frame(stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    stream.write_all(&(data.len() as u64).to_be_bytes())?;
+    stream.write_all(data)
+}
+
+fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len_bytes = [0u8; 8];
+    stream.read_exact(&mut len_bytes)?;
+    let len = u64::from_be_bytes(len_bytes) as usize;
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn send_launch_request(stream: &mut TcpStream, request: &LaunchRequest) -> io::Result<()> {
+    write_frame(stream, request.target_triple.as_bytes())?;
+    write_frame(stream, request.executable)?;
+    write_frame(stream, &(request.criterion_home_files.len() as u64).to_be_bytes())?;
+    for (path, contents) in request.criterion_home_files {
+        write_frame(stream, path.to_string_lossy().as_bytes())?;
+        write_frame(stream, contents)?;
+    }
+    let args: Vec<u8> = request
+        .args
+        .iter()
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("\u{0}")
+        .into_bytes();
+    write_frame(stream, &args)?;
+    write_frame(stream, request.tls_fingerprint.unwrap_or("").as_bytes())?;
+    Ok(())
+}
+
+#[allow(clippy::type_complexity)]
+fn recv_launch_request(
+    stream: &mut TcpStream,
+) -> Result<
+    (
+        String,
+        Vec<u8>,
+        Vec<(PathBuf, Vec<u8>)>,
+        Vec<std::ffi::OsString>,
+        Option<String>,
+    ),
+    AgentError,
+> {
+    let target_triple = String::from_utf8_lossy(&read_frame(stream)?).into_owned();
+    let executable = read_frame(stream)?;
+
+    let mut count_bytes = [0u8; 8];
+    let raw = read_frame(stream)?;
+    count_bytes.copy_from_slice(&raw[..8]);
+    let count = u64::from_be_bytes(count_bytes);
+
+    let mut files = Vec::new();
+    for _ in 0..count {
+        let path = PathBuf::from(String::from_utf8_lossy(&read_frame(stream)?).into_owned());
+        let contents = read_frame(stream)?;
+        files.push((path, contents));
+    }
+
+    let args_frame = read_frame(stream)?;
+    let args_str = String::from_utf8_lossy(&args_frame);
+    let args = if args_str.is_empty() {
+        Vec::new()
+    } else {
+        args_str.split('\u{0}').map(std::ffi::OsString::from).collect()
+    };
+
+    let fingerprint_frame = read_frame(stream)?;
+    let tls_fingerprint = if fingerprint_frame.is_empty() {
+        None
+    } else {
+        Some(String::from_utf8_lossy(&fingerprint_frame).into_owned())
+    };
+
+    Ok((target_triple, executable, files, args, tls_fingerprint))
+}
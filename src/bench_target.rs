@@ -1,10 +1,60 @@
+use crate::agent::{self, AgentError};
 use crate::connection::{
     Connection, ConnectionError, IncomingMessage, MessageError, OutgoingMessage,
 };
+use crate::replay::{Player, Recorder, ReplayError};
+use polling::{Event, Poller};
 use std::ffi::OsString;
-use std::net::TcpListener;
-use std::path::PathBuf;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+/// Default for `BenchTarget::connect_timeout`: how long to wait for a
+/// spawned benchmark to connect back to us, or for a message to arrive
+/// once it has, before giving up on it.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Where a `BenchTarget`'s executable should be run.
+#[derive(Debug, Clone)]
+pub enum TargetLocation {
+    /// Spawn the executable on this machine.
+    Local,
+    /// Ship the executable to the agent daemon listening at this address and
+    /// run it there instead.
+    Remote(SocketAddr),
+}
+
+/// A buffered sink for the lines a running target would otherwise print
+/// directly to stdout.
+///
+/// When several targets run concurrently (see `crate::scheduler`) writing
+/// straight to stdout would interleave their output, so each target is
+/// given one of these to collect into instead; the scheduler is responsible
+/// for flushing the buffers to stdout in a stable order once the target
+/// finishes.
+#[derive(Debug, Default)]
+pub struct EventSink {
+    lines: Vec<String>,
+}
+impl EventSink {
+    pub fn new() -> Self {
+        EventSink { lines: Vec::new() }
+    }
+
+    fn push(&mut self, line: String) {
+        self.lines.push(line);
+    }
+
+    /// Write every buffered line to stdout, in the order they were
+    /// recorded, then clear the buffer.
+    pub fn flush_to_stdout(&mut self) {
+        for line in self.lines.drain(..) {
+            println!("{}", line);
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum TargetError {
@@ -12,6 +62,10 @@ pub enum TargetError {
     TargetFailed(String, ExitStatus),
     MessageError(String, MessageError),
     ConnectionError(String, ConnectionError),
+    RemoteError(String, AgentError),
+    Timeout(String),
+    ReplayError(String, ReplayError),
+    Panicked(String, String),
 }
 impl std::fmt::Display for TargetError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -36,6 +90,26 @@ impl std::fmt::Display for TargetError {
                 "Unexpected error connecting to benchmark target '{}':\n{}",
                 target_name, connection_error
             ),
+            TargetError::RemoteError(target_name, agent_error) => write!(
+                f,
+                "Unexpected error running benchmark target '{}' on remote host:\n{}",
+                target_name, agent_error
+            ),
+            TargetError::Timeout(target_name) => write!(
+                f,
+                "Benchmark target '{}' did not respond within the connect timeout.",
+                target_name
+            ),
+            TargetError::ReplayError(target_name, replay_error) => write!(
+                f,
+                "Unable to replay recorded session for benchmark target '{}':\n{}",
+                target_name, replay_error
+            ),
+            TargetError::Panicked(target_name, message) => write!(
+                f,
+                "Benchmark target '{}' panicked while running:\n{}",
+                target_name, message
+            ),
         }
     }
 }
@@ -46,6 +120,10 @@ impl std::error::Error for TargetError {
             TargetError::IoError(_, io_error) => Some(io_error),
             TargetError::MessageError(_, message_error) => Some(message_error),
             TargetError::ConnectionError(_, connection_error) => Some(connection_error),
+            TargetError::RemoteError(_, agent_error) => Some(agent_error),
+            TargetError::Timeout(_) => None,
+            TargetError::ReplayError(_, replay_error) => Some(replay_error),
+            TargetError::Panicked(_, _) => None,
         }
     }
 }
@@ -55,12 +133,85 @@ impl std::error::Error for TargetError {
 pub struct BenchTarget {
     pub name: String,
     pub executable: PathBuf,
+    pub location: TargetLocation,
+    /// Whether to negotiate TLS over the connection to this target. Set
+    /// from `--secure`, or forced on automatically for `Remote` targets
+    /// since those traffic over a real network interface rather than
+    /// loopback.
+    pub secure: bool,
+    /// When set (`--record <file>`), every message received from this
+    /// target is appended to the given session file as it arrives.
+    pub record: Option<PathBuf>,
+    /// When set (`--replay <file>`), the target is not spawned at all;
+    /// instead the session file is fed back through the same message
+    /// handling code. Takes priority over `location`.
+    pub replay: Option<PathBuf>,
+    /// The target triple `executable` was compiled for. Sent to the remote
+    /// agent so it can refuse to run a binary built for the wrong platform
+    /// instead of silently failing to spawn it; ignored for `Local`
+    /// targets. The caller is expected to pass the actual triple the
+    /// build used (e.g. from `rustc -vV` or the build invocation), not a
+    /// compile-time env var of `cargo-criterion` itself.
+    pub target_triple: String,
+    /// How long to wait for the target to connect back, or for a message
+    /// to arrive once it has, before giving up on it. Defaults to
+    /// `DEFAULT_CONNECT_TIMEOUT`; overridable with `--connect-timeout`.
+    pub connect_timeout: Duration,
 }
 impl BenchTarget {
     pub fn execute(
         &self,
         criterion_home: &PathBuf,
         additional_args: &[OsString],
+        events: &mut EventSink,
+    ) -> Result<(), TargetError> {
+        if let Some(replay_path) = &self.replay {
+            return self.execute_replay(replay_path, events);
+        }
+        match &self.location {
+            TargetLocation::Local => self.execute_local(criterion_home, additional_args, events),
+            TargetLocation::Remote(addr) => {
+                self.execute_remote(*addr, criterion_home, additional_args, events)
+            }
+        }
+    }
+
+    /// Replay a previously recorded session instead of spawning the target,
+    /// driving `handle_message` exactly as a live run would.
+    fn execute_replay(
+        &self,
+        replay_path: &Path,
+        events: &mut EventSink,
+    ) -> Result<(), TargetError> {
+        let mut player =
+            Player::open(replay_path).map_err(|err| TargetError::ReplayError(self.name.clone(), err))?;
+        while let Some(message) = player.next_message() {
+            self.handle_message(message, events)?;
+        }
+        Ok(())
+    }
+
+    fn open_recorder(&self) -> Result<Option<Recorder>, TargetError> {
+        match &self.record {
+            Some(path) => Recorder::create(path)
+                .map(Some)
+                .map_err(|err| TargetError::IoError(self.name.clone(), err)),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether this target's connection should be TLS-secured: either the
+    /// user asked for it explicitly, or it's implied by running on a
+    /// `Remote` host.
+    fn wants_secure(&self) -> bool {
+        self.secure || matches!(self.location, TargetLocation::Remote(_))
+    }
+
+    fn execute_local(
+        &self,
+        criterion_home: &PathBuf,
+        additional_args: &[OsString],
+        events: &mut EventSink,
     ) -> Result<(), TargetError> {
         let listener = TcpListener::bind("localhost:0")
             .map_err(|err| TargetError::IoError(self.name.clone(), err))?;
@@ -73,6 +224,16 @@ impl BenchTarget {
             .map_err(|err| TargetError::IoError(self.name.clone(), err))?;
         let port = addr.port();
 
+        #[cfg(feature = "secure")]
+        let cert = if self.wants_secure() {
+            Some(
+                crate::tls::EphemeralCert::generate()
+                    .map_err(|err| TargetError::IoError(self.name.clone(), io_error(err)))?,
+            )
+        } else {
+            None
+        };
+
         let mut command = Command::new(&self.executable);
         command
             .arg("--bench")
@@ -83,111 +244,611 @@ impl BenchTarget {
             .stderr(Stdio::inherit())
             .stdout(Stdio::inherit());
 
-        println!("{:?}", command);
+        #[cfg(feature = "secure")]
+        if let Some(cert) = &cert {
+            command.env("CARGO_CRITERION_TLS_FINGERPRINT", cert.fingerprint());
+        }
+
+        events.push(format!("{:?}", command));
 
-        let mut child = command
+        let child = command
             .spawn()
             .map_err(|err| TargetError::IoError(self.name.clone(), err))?;
 
+        let poller = Arc::new(Poller::new().map_err(|err| TargetError::IoError(self.name.clone(), err))?);
+        poller
+            .add(&listener, Event::readable(0))
+            .map_err(|err| TargetError::IoError(self.name.clone(), err))?;
+
+        // Wakes the poller as soon as the child exits, instead of only
+        // noticing once the connect deadline happens to elapse.
+        let watcher = ChildWatcher::spawn(child, Arc::clone(&poller));
+
+        let deadline = Instant::now() + self.connect_timeout;
+        let mut events_buf = Vec::new();
         loop {
+            if let Some(outcome) = watcher.try_exit_status() {
+                return match outcome {
+                    Ok(exit_status) if exit_status.success() => {
+                        events.push("Child exited successfully".to_string());
+                        Ok(())
+                    }
+                    Ok(exit_status) => {
+                        events.push("Child terminated".to_string());
+                        Err(TargetError::TargetFailed(self.name.clone(), exit_status))
+                    }
+                    Err(err) => {
+                        events.push("Failed to poll child process".to_string());
+                        Err(TargetError::IoError(self.name.clone(), err))
+                    }
+                };
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                watcher.kill_and_wait();
+                return Err(TargetError::Timeout(self.name.clone()));
+            }
+
+            events_buf.clear();
+            poller
+                .wait(&mut events_buf, Some(remaining))
+                .map_err(|err| TargetError::IoError(self.name.clone(), err))?;
+            if events_buf.is_empty() {
+                // Timeout, spurious wakeup, or the child watcher's
+                // `notify()` (which delivers no event); loop back around so
+                // the child-exit check and the deadline are re-checked.
+                continue;
+            }
+
             match listener.accept() {
                 Ok((socket, _)) => {
-                    let conn = Connection::new(socket)
+                    #[cfg(feature = "secure")]
+                    let conn = match &cert {
+                        Some(cert) => {
+                            let secure_socket = crate::tls::SecureStream::accept(socket, cert)
+                                .map_err(|err| TargetError::IoError(self.name.clone(), err))?;
+                            Connection::new(secure_socket)
+                        }
+                        None => Connection::new(socket),
+                    };
+                    #[cfg(not(feature = "secure"))]
+                    let conn = Connection::new(socket);
+
+                    let conn = conn
                         .map_err(|err| TargetError::ConnectionError(self.name.clone(), err))?;
-                    return self.communicate(&mut child, conn);
+                    // Reaped by `communicate`'s own child-polling from here
+                    // on (`poll_child_exit`/`finish_child`), not by us.
+                    #[allow(clippy::zombie_processes)]
+                    match watcher.stop_and_reclaim() {
+                        Reclaimed::Running(mut child) => {
+                            let mut recorder = self.open_recorder()?;
+                            return self.communicate(&mut child, conn, events, recorder.as_mut());
+                        }
+                        Reclaimed::AlreadyExited(outcome) => {
+                            return match outcome {
+                                Ok(exit_status) if exit_status.success() => {
+                                    events.push("Child exited successfully".to_string());
+                                    Ok(())
+                                }
+                                Ok(exit_status) => {
+                                    events.push("Child terminated".to_string());
+                                    Err(TargetError::TargetFailed(self.name.clone(), exit_status))
+                                }
+                                Err(err) => {
+                                    events.push("Failed to poll child process".to_string());
+                                    Err(TargetError::IoError(self.name.clone(), err))
+                                }
+                            };
+                        }
+                    }
                 }
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // No connection yet, try again in a bit.
+                    poller
+                        .modify(&listener, Event::readable(0))
+                        .map_err(|err| TargetError::IoError(self.name.clone(), err))?;
                 }
                 Err(e) => {
-                    println!("Failed to accept connection");
+                    events.push("Failed to accept connection".to_string());
                     return Err(TargetError::IoError(self.name.clone(), e));
                 }
             };
+        }
+    }
 
-            match child.try_wait() {
-                Err(e) => {
-                    println!("Failed to poll child process");
-                    return Err(TargetError::IoError(self.name.clone(), e));
-                }
-                Ok(Some(exit_status)) => {
-                    if exit_status.success() {
-                        println!("Child exited successfully");
-                        return Ok(());
-                    } else {
-                        println!("Child terminated");
-                        return Err(TargetError::TargetFailed(self.name.clone(), exit_status));
+    /// Check whether `child` has exited, recording the outcome to `events`
+    /// on failure to poll. `Ok(None)` means it is still running.
+    fn poll_child_exit(
+        &self,
+        child: &mut Child,
+        events: &mut EventSink,
+    ) -> Result<Option<ExitStatus>, TargetError> {
+        child.try_wait().map_err(|e| {
+            events.push("Failed to poll child process".to_string());
+            TargetError::IoError(self.name.clone(), e)
+        })
+    }
+
+    /// Ship the executable and `CRITERION_HOME` to the agent daemon at
+    /// `addr`, have it launch the benchmark there, and tunnel the resulting
+    /// connection back through to the usual message-handling code.
+    fn execute_remote(
+        &self,
+        addr: SocketAddr,
+        criterion_home: &PathBuf,
+        additional_args: &[OsString],
+        events: &mut EventSink,
+    ) -> Result<(), TargetError> {
+        #[cfg(feature = "secure")]
+        let cert = if self.wants_secure() {
+            Some(
+                crate::tls::EphemeralCert::generate()
+                    .map_err(|err| TargetError::IoError(self.name.clone(), io_error(err)))?,
+            )
+        } else {
+            None
+        };
+        #[cfg(feature = "secure")]
+        let fingerprint = cert.as_ref().map(|cert| cert.fingerprint());
+        #[cfg(not(feature = "secure"))]
+        let fingerprint: Option<String> = None;
+
+        let handle = agent::launch_remote(
+            addr,
+            &self.target_triple,
+            &self.executable,
+            criterion_home,
+            additional_args,
+            fingerprint.as_deref(),
+        )
+        .map_err(|err| TargetError::RemoteError(self.name.clone(), err))?;
+
+        let socket = std::net::TcpStream::connect((addr.ip(), handle.forwarded_port))
+            .map_err(|err| TargetError::IoError(self.name.clone(), err))?;
+
+        #[cfg(feature = "secure")]
+        let conn = match &cert {
+            Some(cert) => {
+                let secure_socket = crate::tls::SecureStream::accept(socket, cert)
+                    .map_err(|err| TargetError::IoError(self.name.clone(), err))?;
+                Connection::new(secure_socket)
+            }
+            None => Connection::new(socket),
+        };
+        #[cfg(not(feature = "secure"))]
+        let conn = Connection::new(socket);
+
+        let conn = conn.map_err(|err| TargetError::ConnectionError(self.name.clone(), err))?;
+
+        let mut recorder = self.open_recorder()?;
+        let result = self.communicate_remote(conn, events, recorder.as_mut());
+        let _ = handle.kill_and_cleanup();
+        result
+    }
+
+    /// Like `communicate`, but there is no local `Child` to poll: the
+    /// benchmark runs on the remote agent, so the only way to notice it
+    /// has wedged is the same message timeout used for local targets.
+    ///
+    /// `Connection` has no built-in read deadline, so `recv()` runs on a
+    /// dedicated thread (as in `communicate`) and this loop waits on it
+    /// with `recv_timeout` instead of blocking on it directly forever.
+    fn communicate_remote(
+        &self,
+        mut conn: Connection,
+        events: &mut EventSink,
+        recorder: Option<&mut Recorder>,
+    ) -> Result<(), TargetError> {
+        let (outgoing_tx, outgoing_rx) = mpsc::channel::<Option<OutgoingMessage>>();
+        let (incoming_tx, incoming_rx) = mpsc::channel();
+        let reader = std::thread::spawn(move || {
+            while let Ok(outgoing) = outgoing_rx.recv() {
+                if let Some(outgoing) = outgoing {
+                    if let Err(err) = conn.send(&outgoing) {
+                        let _ = incoming_tx.send(Err(err));
+                        return;
                     }
                 }
-                Ok(None) => (), // Child still running, keep trying.
-            };
+                let result = conn.recv();
+                let done = !matches!(result, Ok(Some(_)));
+                let _ = incoming_tx.send(result);
+                if done {
+                    return;
+                }
+            }
+        });
 
-            // Wait a bit then poll again.
-            std::thread::yield_now();
-        }
+        let result = self.communicate_remote_loop(events, recorder, &outgoing_tx, &incoming_rx);
+        drop(outgoing_tx);
+        let _ = reader.join();
+        result
     }
 
-    fn communicate(&self, child: &mut Child, mut conn: Connection) -> Result<(), TargetError> {
+    fn communicate_remote_loop(
+        &self,
+        events: &mut EventSink,
+        mut recorder: Option<&mut Recorder>,
+        outgoing_tx: &mpsc::Sender<Option<OutgoingMessage>>,
+        incoming_rx: &mpsc::Receiver<Result<Option<IncomingMessage>, MessageError>>,
+    ) -> Result<(), TargetError> {
+        outgoing_tx
+            .send(None)
+            .map_err(|_| TargetError::IoError(self.name.clone(), broken_pipe()))?;
+
         loop {
-            let message = conn
-                .recv()
-                .map_err(|err| TargetError::MessageError(self.name.clone(), err))?;
-            if message.is_none() {
-                return Ok(());
-            }
-            let message = message.unwrap();
-            match message {
-                IncomingMessage::BeginningBenchmarkGroup { group } => {
-                    println!("Beginning benchmark group {}", group);
-                }
-                IncomingMessage::FinishedBenchmarkGroup { group } => {
-                    println!("Finished benchmark group {}", group);
-                }
-                IncomingMessage::BeginningBenchmark { id } => {
-                    println!("Beginning benchmark {:?}", id);
-                    conn.send(&OutgoingMessage::RunBenchmark)
+            match incoming_rx.recv_timeout(self.connect_timeout) {
+                Ok(message) => {
+                    let message = message
                         .map_err(|err| TargetError::MessageError(self.name.clone(), err))?;
+                    let message = match message {
+                        None => return Ok(()),
+                        Some(message) => message,
+                    };
+                    if let Some(recorder) = recorder.as_deref_mut() {
+                        recorder
+                            .record(&message)
+                            .map_err(|err| TargetError::IoError(self.name.clone(), err))?;
+                    }
+                    let reply = self.handle_message(message, events)?;
+                    outgoing_tx
+                        .send(reply)
+                        .map_err(|_| TargetError::IoError(self.name.clone(), broken_pipe()))?;
                 }
-                IncomingMessage::SkippingBenchmark { id } => {
-                    println!("Skipping benchmark {:?}", id)
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(TargetError::IoError(self.name.clone(), broken_pipe()));
                 }
-                IncomingMessage::Warmup { id, nanos } => {
-                    println!("Warming up benchmark {:?} for {} nanos", id, nanos)
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    return Err(TargetError::Timeout(self.name.clone()));
                 }
-                IncomingMessage::MeasurementStart {
-                    id,
-                    sample_count,
-                    estimate_ns,
-                    iter_count,
-                    added_runner,
-                } => {
-                    println!("Measuring benchmark {:?} samples: {}, estimated time: {}ns, iterations: {}, {:?}", id, sample_count, estimate_ns, iter_count, added_runner);
+            }
+        }
+    }
+
+    /// Drive the message protocol against a locally-spawned child, blocking
+    /// on the next message with a deadline instead of polling `try_wait`
+    /// after every single message.
+    ///
+    /// `Connection` has no built-in notion of a read deadline, so the
+    /// actual `recv()` call runs on a dedicated thread and we wait on it
+    /// with `recv_timeout`. When that times out we check whether the child
+    /// has exited; if it is still running it is treated as wedged, killed,
+    /// and reported as `TargetError::Timeout`.
+    fn communicate(
+        &self,
+        child: &mut Child,
+        mut conn: Connection,
+        events: &mut EventSink,
+        recorder: Option<&mut Recorder>,
+    ) -> Result<(), TargetError> {
+        let (outgoing_tx, outgoing_rx) = mpsc::channel::<Option<OutgoingMessage>>();
+        let (incoming_tx, incoming_rx) = mpsc::channel();
+        let reader = std::thread::spawn(move || {
+            while let Ok(outgoing) = outgoing_rx.recv() {
+                if let Some(outgoing) = outgoing {
+                    if let Err(err) = conn.send(&outgoing) {
+                        let _ = incoming_tx.send(Err(err));
+                        return;
+                    }
                 }
-                IncomingMessage::MeasurementComplete {
-                    id,
-                    iters: _,
-                    times: _,
-                } => {
-                    println!("Measurement of benchmark {:?} complete", id);
+                let result = conn.recv();
+                let done = !matches!(result, Ok(Some(_)));
+                let _ = incoming_tx.send(result);
+                if done {
+                    return;
                 }
             }
+        });
 
-            match child.try_wait() {
-                Err(e) => {
-                    println!("Failed to poll Criterion.rs child process");
-                    return Err(TargetError::IoError(self.name.clone(), e));
+        let result = self.communicate_loop(child, events, recorder, &outgoing_tx, &incoming_rx);
+        drop(outgoing_tx);
+        let _ = reader.join();
+        result
+    }
+
+    fn communicate_loop(
+        &self,
+        child: &mut Child,
+        events: &mut EventSink,
+        mut recorder: Option<&mut Recorder>,
+        outgoing_tx: &mpsc::Sender<Option<OutgoingMessage>>,
+        incoming_rx: &mpsc::Receiver<Result<Option<IncomingMessage>, MessageError>>,
+    ) -> Result<(), TargetError> {
+        outgoing_tx
+            .send(None)
+            .map_err(|_| TargetError::IoError(self.name.clone(), broken_pipe()))?;
+
+        // Each message gets its own fresh window; only a child that both
+        // stays silent *and* never exits for a full `self.connect_timeout`
+        // is considered wedged.
+        loop {
+            match incoming_rx.recv_timeout(self.connect_timeout) {
+                Ok(message) => {
+                    let message = message
+                        .map_err(|err| TargetError::MessageError(self.name.clone(), err))?;
+                    let message = match message {
+                        None => return Ok(()),
+                        Some(message) => message,
+                    };
+                    if let Some(recorder) = recorder.as_deref_mut() {
+                        recorder
+                            .record(&message)
+                            .map_err(|err| TargetError::IoError(self.name.clone(), err))?;
+                    }
+                    let reply = self.handle_message(message, events)?;
+                    outgoing_tx
+                        .send(reply)
+                        .map_err(|_| TargetError::IoError(self.name.clone(), broken_pipe()))?;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(TargetError::IoError(self.name.clone(), broken_pipe()));
                 }
-                Ok(Some(exit_status)) => {
-                    if exit_status.success() {
-                        println!("Criterion.rs child exited successfully");
-                        return Ok(());
-                    } else {
-                        println!("Criterion.rs child terminated unsuccessfully");
-                        return Err(TargetError::TargetFailed(self.name.clone(), exit_status));
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if let Some(exit_status) = self.poll_child_exit(child, events)? {
+                        return self.finish_child(exit_status, events);
                     }
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(TargetError::Timeout(self.name.clone()));
                 }
-                Ok(None) => continue,
-            };
+            }
         }
     }
+
+    fn finish_child(
+        &self,
+        exit_status: ExitStatus,
+        events: &mut EventSink,
+    ) -> Result<(), TargetError> {
+        if exit_status.success() {
+            events.push("Criterion.rs child exited successfully".to_string());
+            Ok(())
+        } else {
+            events.push("Criterion.rs child terminated unsuccessfully".to_string());
+            Err(TargetError::TargetFailed(self.name.clone(), exit_status))
+        }
+    }
+
+    fn handle_message(
+        &self,
+        message: IncomingMessage,
+        events: &mut EventSink,
+    ) -> Result<Option<OutgoingMessage>, TargetError> {
+        let mut reply = None;
+        match message {
+            IncomingMessage::BeginningBenchmarkGroup { group } => {
+                events.push(format!("Beginning benchmark group {}", group));
+            }
+            IncomingMessage::FinishedBenchmarkGroup { group } => {
+                events.push(format!("Finished benchmark group {}", group));
+            }
+            IncomingMessage::BeginningBenchmark { id } => {
+                events.push(format!("Beginning benchmark {:?}", id));
+                reply = Some(OutgoingMessage::RunBenchmark);
+            }
+            IncomingMessage::SkippingBenchmark { id } => {
+                events.push(format!("Skipping benchmark {:?}", id));
+            }
+            IncomingMessage::Warmup { id, nanos } => {
+                events.push(format!("Warming up benchmark {:?} for {} nanos", id, nanos));
+            }
+            IncomingMessage::MeasurementStart {
+                id,
+                sample_count,
+                estimate_ns,
+                iter_count,
+                added_runner,
+            } => {
+                events.push(format!(
+                    "Measuring benchmark {:?} samples: {}, estimated time: {}ns, iterations: {}, {:?}",
+                    id, sample_count, estimate_ns, iter_count, added_runner
+                ));
+            }
+            IncomingMessage::MeasurementComplete {
+                id,
+                iters: _,
+                times: _,
+            } => {
+                events.push(format!("Measurement of benchmark {:?} complete", id));
+            }
+        }
+        Ok(reply)
+    }
+}
+
+/// Command sent from the poller loop to the thread owning the watched
+/// child.
+enum WatcherCommand {
+    /// Kill the child; keep watching until it actually exits.
+    Kill,
+    /// Stop watching and hand the child back so its owner can take over
+    /// polling it directly (used once a connection has been accepted).
+    Stop,
+}
+
+/// Owns a spawned child on a dedicated thread and wakes a `Poller` the
+/// moment it exits, so a caller blocked in `poller.wait()` finds out
+/// immediately instead of only once its deadline elapses.
+///
+/// There is no portable, dependency-free way to get an OS-level wakeup on
+/// process exit, so this polls `try_wait` on a short interval internally;
+/// what makes it useful is that it calls `Poller::notify` itself rather
+/// than requiring the caller to keep re-checking.
+struct ChildWatcher {
+    exit_rx: mpsc::Receiver<Result<ExitStatus, std::io::Error>>,
+    control_tx: mpsc::Sender<WatcherCommand>,
+    reclaim_rx: mpsc::Receiver<Child>,
+    handle: std::thread::JoinHandle<()>,
+}
+impl ChildWatcher {
+    const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+    fn spawn(mut child: Child, poller: Arc<Poller>) -> Self {
+        let (exit_tx, exit_rx) = mpsc::channel();
+        let (control_tx, control_rx) = mpsc::channel();
+        let (reclaim_tx, reclaim_rx) = mpsc::channel();
+
+        let handle = std::thread::spawn(move || loop {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    let _ = exit_tx.send(Ok(status));
+                    let _ = poller.notify();
+                    return;
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    let _ = exit_tx.send(Err(err));
+                    let _ = poller.notify();
+                    return;
+                }
+            }
+            match control_rx.try_recv() {
+                Ok(WatcherCommand::Kill) => {
+                    let _ = child.kill();
+                }
+                Ok(WatcherCommand::Stop) => {
+                    let _ = reclaim_tx.send(child);
+                    return;
+                }
+                Err(_) => {}
+            }
+            std::thread::sleep(Self::POLL_INTERVAL);
+        });
+
+        ChildWatcher {
+            exit_rx,
+            control_tx,
+            reclaim_rx,
+            handle,
+        }
+    }
+
+    /// The child's exit status, if it has exited since this was last
+    /// called. `None` means it is still running.
+    fn try_exit_status(&self) -> Option<Result<ExitStatus, std::io::Error>> {
+        self.exit_rx.try_recv().ok()
+    }
+
+    /// Kill the child and block until the watcher thread confirms it has
+    /// been reaped.
+    fn kill_and_wait(self) {
+        let _ = self.control_tx.send(WatcherCommand::Kill);
+        let _ = self.exit_rx.recv();
+        let _ = self.handle.join();
+    }
+
+    /// Stop watching and take the child back, e.g. because a connection
+    /// has been accepted and `communicate`'s own child-polling takes over
+    /// from here.
+    ///
+    /// The child may have already exited in the window between the poller
+    /// waking us up and this call reaching the watcher thread; in that case
+    /// there is no `Child` left to hand back, so the watcher thread's exit
+    /// outcome is returned instead of one.
+    fn stop_and_reclaim(self) -> Reclaimed {
+        let _ = self.control_tx.send(WatcherCommand::Stop);
+        let reclaimed = match self.reclaim_rx.recv() {
+            Ok(child) => Reclaimed::Running(child),
+            Err(_) => {
+                // The watcher thread saw the child exit before it processed
+                // our `Stop`, sent the outcome on `exit_tx`, and returned
+                // without ever sending on `reclaim_tx`. Take that outcome.
+                let outcome = self
+                    .exit_rx
+                    .recv()
+                    .expect("watcher thread exited without reporting an outcome");
+                Reclaimed::AlreadyExited(outcome)
+            }
+        };
+        let _ = self.handle.join();
+        reclaimed
+    }
+}
+
+/// Outcome of [`ChildWatcher::stop_and_reclaim`].
+enum Reclaimed {
+    /// The child was still running and is handed back for direct ownership.
+    Running(Child),
+    /// The child had already exited before the `Stop` command was
+    /// processed; here is how it exited.
+    AlreadyExited(Result<ExitStatus, std::io::Error>),
+}
+
+fn broken_pipe() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "connection reader thread terminated unexpectedly",
+    )
+}
+
+#[cfg(feature = "secure")]
+fn io_error(err: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::other(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::Recorder;
+
+    fn unique_session_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "cargo-criterion-replay-test-{}-{}.ndjson",
+            std::process::id(),
+            name
+        ))
+    }
+
+    fn test_target(replay_path: PathBuf) -> BenchTarget {
+        BenchTarget {
+            name: "golden".to_string(),
+            executable: PathBuf::new(),
+            location: TargetLocation::Local,
+            secure: false,
+            record: None,
+            replay: Some(replay_path),
+            target_triple: "unknown".to_string(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+
+    /// A recorded session, replayed through the same `handle_message` code
+    /// a live run would use, must reproduce exactly the console output the
+    /// live run originally produced.
+    #[test]
+    fn replay_reproduces_recorded_session_output() {
+        let session_path = unique_session_path("golden");
+        {
+            let mut recorder = Recorder::create(&session_path).expect("create recorder");
+            recorder
+                .record(&IncomingMessage::BeginningBenchmarkGroup {
+                    group: "group_a".to_string(),
+                })
+                .expect("record message");
+            recorder
+                .record(&IncomingMessage::SkippingBenchmark {
+                    id: "bench_1".to_string(),
+                })
+                .expect("record message");
+            recorder
+                .record(&IncomingMessage::FinishedBenchmarkGroup {
+                    group: "group_a".to_string(),
+                })
+                .expect("record message");
+        }
+
+        let target = test_target(session_path.clone());
+        let mut events = EventSink::new();
+        let result = target.execute(&PathBuf::new(), &[], &mut events);
+        let _ = std::fs::remove_file(&session_path);
+        result.expect("replay should succeed");
+
+        assert_eq!(
+            events.lines,
+            vec![
+                "Beginning benchmark group group_a".to_string(),
+                "Skipping benchmark \"bench_1\"".to_string(),
+                "Finished benchmark group group_a".to_string(),
+            ]
+        );
+    }
 }
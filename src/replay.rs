@@ -0,0 +1,111 @@
+//! Record-and-replay support for the `communicate` message loop.
+//!
+//! In record mode, every `IncomingMessage` we receive from a target is
+//! timestamped and appended to a session file. In replay mode,
+//! `BenchTarget::execute` skips spawning a process entirely and feeds a
+//! previously recorded session back through the same `handle_message` code,
+//! so `cargo-criterion`'s message handling can be exercised deterministically
+//! without rebuilding or re-running the (possibly expensive) benchmark.
+//!
+//! Sessions are stored as newline-delimited JSON so they're easy to diff and
+//! to use as the "golden" expected output in a regression test.
+
+use crate::connection::IncomingMessage;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RecordedMessage {
+    elapsed_nanos: u128,
+    message: IncomingMessage,
+}
+
+/// Appends every message passed to `record` to a session file, timestamped
+/// relative to when the `Recorder` was created.
+pub struct Recorder {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Recorder {
+            writer: BufWriter::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, message: &IncomingMessage) -> io::Result<()> {
+        let entry = RecordedMessage {
+            elapsed_nanos: self.start.elapsed().as_nanos(),
+            message: message.clone(),
+        };
+        let line = serde_json::to_string(&entry)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        writeln!(self.writer, "{}", line)?;
+        self.writer.flush()
+    }
+}
+
+/// Feeds a previously recorded session back as a sequence of
+/// `IncomingMessage`s, in the order they were originally received. The
+/// recorded timestamps are parsed but not used to pace playback: each
+/// message is returned as soon as it is asked for.
+pub struct Player {
+    messages: std::vec::IntoIter<(Duration, IncomingMessage)>,
+}
+impl Player {
+    pub fn open(path: &Path) -> Result<Self, ReplayError> {
+        let file = File::open(path).map_err(ReplayError::Io)?;
+        let mut messages = Vec::new();
+        for (line_number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(ReplayError::Io)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: RecordedMessage = serde_json::from_str(&line)
+                .map_err(|err| ReplayError::MalformedSession(line_number + 1, err.to_string()))?;
+            messages.push((
+                Duration::from_nanos(entry.elapsed_nanos.min(u64::MAX as u128) as u64),
+                entry.message,
+            ));
+        }
+        Ok(Player {
+            messages: messages.into_iter(),
+        })
+    }
+
+    /// Returns the next recorded message, if any. The caller drives
+    /// `handle_message` with it exactly as it would a live message.
+    pub fn next_message(&mut self) -> Option<IncomingMessage> {
+        self.messages.next().map(|(_elapsed, message)| message)
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    MalformedSession(usize, String),
+}
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::Io(err) => write!(f, "Unable to read session file: {}", err),
+            ReplayError::MalformedSession(line, reason) => write!(
+                f,
+                "Malformed session file at line {}: {}",
+                line, reason
+            ),
+        }
+    }
+}
+impl std::error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReplayError::Io(err) => Some(err),
+            ReplayError::MalformedSession(_, _) => None,
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Runs a batch of `BenchTarget`s across a bounded worker pool instead of
+//! one at a time.
+//!
+//! Each target already binds its own ephemeral `TcpListener`, so running
+//! several of them at once is safe; the only shared resource is stdout,
+//! which is why `BenchTarget::execute` writes into an `EventSink` instead of
+//! printing directly. The scheduler drains each target's sink once it
+//! finishes, in the same order the targets were given, so concurrent runs
+//! produce the same log ordering as a serial run would.
+
+use crate::bench_target::{BenchTarget, EventSink, TargetError};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Arc;
+
+/// Number of targets to run at once. Defaults to the number of available
+/// cores, overridable with `--jobs`.
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+struct Completed {
+    index: usize,
+    events: EventSink,
+    result: Result<(), TargetError>,
+}
+
+/// Run every target in `targets`, at most `jobs` at a time, returning the
+/// first error encountered (if any) once every already-running target has
+/// finished or been cancelled.
+pub fn execute_all(
+    targets: Vec<BenchTarget>,
+    criterion_home: &PathBuf,
+    additional_args: &[OsString],
+    jobs: usize,
+) -> Result<(), TargetError> {
+    let jobs = jobs.max(1);
+    let criterion_home = Arc::new(criterion_home.clone());
+    let additional_args = Arc::new(additional_args.to_vec());
+
+    let (work_tx, work_rx) = mpsc::channel::<(usize, BenchTarget)>();
+    let work_rx = Arc::new(std::sync::Mutex::new(work_rx));
+    let (done_tx, done_rx) = mpsc::channel::<Completed>();
+
+    let total = targets.len();
+    for (index, target) in targets.into_iter().enumerate() {
+        work_tx
+            .send((index, target))
+            .expect("worker pool receivers dropped before all work was sent");
+    }
+    drop(work_tx);
+
+    let mut workers = Vec::with_capacity(jobs);
+    for _ in 0..jobs {
+        let work_rx = Arc::clone(&work_rx);
+        let done_tx = done_tx.clone();
+        let criterion_home = Arc::clone(&criterion_home);
+        let additional_args = Arc::clone(&additional_args);
+
+        workers.push(std::thread::spawn(move || loop {
+            let next = { work_rx.lock().unwrap().recv() };
+            let (index, target) = match next {
+                Ok(item) => item,
+                Err(_) => break,
+            };
+
+            let mut events = EventSink::new();
+            let target_name = target.name.clone();
+            let result = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                target.execute(&criterion_home, &additional_args, &mut events)
+            })) {
+                Ok(result) => result,
+                Err(payload) => Err(TargetError::Panicked(target_name, panic_message(&payload))),
+            };
+            if done_tx
+                .send(Completed {
+                    index,
+                    events,
+                    result,
+                })
+                .is_err()
+            {
+                break;
+            }
+        }));
+    }
+    drop(done_tx);
+
+    // Buffer out-of-order completions and drain them from the front once
+    // their turn comes up, so output stays in submission order.
+    let mut pending: Vec<Option<Completed>> = (0..total).map(|_| None).collect();
+    let mut next_to_drain = 0;
+    let mut first_error = None;
+
+    for completed in done_rx {
+        let index = completed.index;
+        pending[index] = Some(completed);
+
+        while next_to_drain < total {
+            let mut slot = match pending[next_to_drain].take() {
+                Some(slot) => slot,
+                None => break,
+            };
+            slot.events.flush_to_stdout();
+            if first_error.is_none() {
+                if let Err(err) = std::mem::replace(&mut slot.result, Ok(())) {
+                    first_error = Some(err);
+                }
+            }
+            next_to_drain += 1;
+        }
+    }
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    match first_error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Best-effort extraction of a message from a `catch_unwind` payload, for
+/// reporting a worker panic as a `TargetError` instead of just "something".
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
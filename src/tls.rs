@@ -0,0 +1,85 @@
+//! Optional TLS transport for the connection between `cargo-criterion` and a
+//! benchmark target, gated behind the `secure` cargo feature.
+//!
+//! Benchmarks on a shared or remote host otherwise exchange
+//! `IncomingMessage`/`OutgoingMessage` traffic over a plaintext socket. When
+//! enabled, we generate a fresh self-signed certificate for the run, wrap
+//! the accepted socket in a `rustls` server session, and hand the target
+//! the certificate's fingerprint (via `CARGO_CRITERION_TLS_FINGERPRINT`) so
+//! it can pin it instead of trusting a CA.
+
+#![cfg(feature = "secure")]
+
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection, StreamOwned};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+/// A fresh, run-scoped self-signed certificate and the key that signed it.
+pub struct EphemeralCert {
+    pub cert_der: Vec<u8>,
+    key_der: Vec<u8>,
+}
+impl EphemeralCert {
+    /// Generate a new self-signed certificate for `localhost`. The
+    /// certificate and its key are kept in memory only and never written to
+    /// disk; each call produces a different one.
+    pub fn generate() -> Result<Self, rcgen::RcgenError> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        Ok(EphemeralCert {
+            cert_der: cert.serialize_der()?,
+            key_der: cert.serialize_private_key_der(),
+        })
+    }
+
+    /// The SHA-256 fingerprint of the certificate, hex-encoded, suitable for
+    /// passing to the child via `CARGO_CRITERION_TLS_FINGERPRINT` so it can
+    /// pin it rather than validate against a CA.
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(&self.cert_der);
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn server_config(&self) -> Result<ServerConfig, rustls::Error> {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(self.cert_der.clone())],
+                PrivateKey(self.key_der.clone()),
+            )
+    }
+}
+
+/// A TLS-wrapped connection to a benchmark target. Implements `Read` and
+/// `Write` so it can be handed to `Connection::new` just like a plain
+/// `TcpStream`.
+pub struct SecureStream(StreamOwned<ServerConnection, TcpStream>);
+impl SecureStream {
+    /// Wrap an already-established socket (accepted locally, or connected
+    /// to a remote agent's forwarded port) in a server-side TLS session
+    /// using `cert` for this run. The handshake itself runs on the first
+    /// read or write, i.e. inside `Connection::new`.
+    pub fn accept(socket: TcpStream, cert: &EphemeralCert) -> io::Result<Self> {
+        let config = cert
+            .server_config()
+            .map_err(io::Error::other)?;
+        let connection = ServerConnection::new(Arc::new(config))
+            .map_err(io::Error::other)?;
+        Ok(SecureStream(StreamOwned::new(connection, socket)))
+    }
+}
+impl Read for SecureStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+impl Write for SecureStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}